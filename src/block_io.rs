@@ -0,0 +1,142 @@
+//! A small block-oriented IO layer sitting between [`crate::MountedVolume`]
+//! and its backing store, in the spirit of nod-rs's `DiscReader` and
+//! nyanpass's shared-buffer data accessor: random-access reads/writes of
+//! fixed-size data units, with a bounded number of recently decrypted units
+//! cached so repeated FAT traversals over the same sectors don't repeatedly
+//! decrypt them.
+
+use std::{collections::HashMap, io};
+
+/// The size of a VeraCrypt data unit, the granularity at which volumes are
+/// encrypted and decrypted.
+pub(crate) const DATA_UNIT_SIZE: usize = 512;
+
+/// A backing store that can be read and written in fixed-size data units at
+/// arbitrary absolute offsets, independent of any stream's current cursor
+/// position. Implemented for anything seekable, so alternate backing
+/// stores (split volume files, in-memory buffers) can plug in behind the
+/// same decrypt path as a plain `File`.
+pub(crate) trait BlockIO {
+    fn read_unit(&mut self, offset: u64, buf: &mut [u8; DATA_UNIT_SIZE]) -> io::Result<()>;
+    fn write_unit(&mut self, offset: u64, buf: &[u8; DATA_UNIT_SIZE]) -> io::Result<()>;
+}
+
+impl<D: io::Read + io::Write + io::Seek> BlockIO for D {
+    fn read_unit(&mut self, offset: u64, buf: &mut [u8; DATA_UNIT_SIZE]) -> io::Result<()> {
+        self.seek(io::SeekFrom::Start(offset))?;
+        self.read_exact(buf)
+    }
+
+    fn write_unit(&mut self, offset: u64, buf: &[u8; DATA_UNIT_SIZE]) -> io::Result<()> {
+        self.seek(io::SeekFrom::Start(offset))?;
+        self.write_all(buf)
+    }
+}
+
+/// How many data units [`BlockCache`] keeps decrypted at once before
+/// evicting the least recently used one. 256 units is 128KiB of plaintext,
+/// comfortably ahead of a typical FAT traversal's working set without
+/// holding the whole volume in memory.
+const CACHE_CAPACITY: usize = 256;
+
+/// A cached data unit alongside the tick it was last used at, so the least
+/// recently used entry can be found on eviction.
+struct CachedUnit {
+    data: [u8; DATA_UNIT_SIZE],
+    last_used: u64,
+}
+
+/// A decrypting, caching reader/writer over a [`BlockIO`] backing store.
+/// Units are keyed by their absolute offset in the backing store and, once
+/// decrypted, stay cached so sequential FAT traversals don't repeatedly
+/// decrypt the same sectors. Bounded to [`CACHE_CAPACITY`] units, evicting
+/// the least recently used on overflow, so a full volume traversal doesn't
+/// grow the cache into an in-memory copy of the decrypted volume.
+pub(crate) struct BlockCache<IO: BlockIO> {
+    io: IO,
+    units: HashMap<u64, CachedUnit>,
+    clock: u64,
+}
+
+impl<IO: BlockIO> BlockCache<IO> {
+    pub(crate) fn new(io: IO) -> Self {
+        Self {
+            io,
+            units: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Advance the cache's logical clock, returning the new tick.
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Evict the least recently used unit if the cache is over capacity.
+    fn evict_if_full(&mut self) {
+        if self.units.len() <= CACHE_CAPACITY {
+            return;
+        }
+
+        let lru_offset = *self
+            .units
+            .iter()
+            .min_by_key(|(_, unit)| unit.last_used)
+            .map(|(offset, _)| offset)
+            .expect("cache is non-empty, having just exceeded capacity");
+        self.units.remove(&lru_offset);
+    }
+
+    /// Fetch the decrypted contents of the data unit at `offset`, reading
+    /// and decrypting it via `decrypt` only on a cache miss.
+    pub(crate) fn get(
+        &mut self,
+        offset: u64,
+        decrypt: impl FnOnce(&mut [u8; DATA_UNIT_SIZE]),
+    ) -> io::Result<&[u8; DATA_UNIT_SIZE]> {
+        if !self.units.contains_key(&offset) {
+            let mut data = [0; DATA_UNIT_SIZE];
+            self.io.read_unit(offset, &mut data)?;
+            decrypt(&mut data);
+            let last_used = self.tick();
+            self.units.insert(offset, CachedUnit { data, last_used });
+            self.evict_if_full();
+        } else {
+            let last_used = self.tick();
+            self.units.get_mut(&offset).unwrap().last_used = last_used;
+        }
+
+        Ok(&self.units[&offset].data)
+    }
+
+    /// Write the already-decrypted `unit` back to the backing store,
+    /// encrypting a copy via `encrypt` first, and update the cache with the
+    /// plaintext so later reads of this offset don't need to hit the store.
+    pub(crate) fn put(
+        &mut self,
+        offset: u64,
+        unit: [u8; DATA_UNIT_SIZE],
+        encrypt: impl FnOnce(&mut [u8; DATA_UNIT_SIZE]),
+    ) -> io::Result<()> {
+        let mut encrypted = unit;
+        encrypt(&mut encrypted);
+        self.io.write_unit(offset, &encrypted)?;
+        let last_used = self.tick();
+        self.units.insert(
+            offset,
+            CachedUnit {
+                data: unit,
+                last_used,
+            },
+        );
+        self.evict_if_full();
+        Ok(())
+    }
+}
+
+impl<IO: BlockIO + io::Write> BlockCache<IO> {
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
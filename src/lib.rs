@@ -1,13 +1,20 @@
-use std::{fs::File, io, path::Path};
+mod block_io;
+mod cipher;
 
-use aes::{
-    cipher::{generic_array::GenericArray, KeyInit},
-    Aes256,
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
 };
+
 use binrw::BinRead;
 use crc::{Crc, CRC_32_ISO_HDLC};
 use fscommon::BufStream;
-use xts_mode::{get_tweak_default, Xts128};
+use xts_mode::get_tweak_default;
+
+use block_io::{BlockCache, DATA_UNIT_SIZE};
+pub use cipher::{Cipher, Prf};
+use cipher::XtsCipher;
 
 static CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
 
@@ -30,9 +37,27 @@ pub struct UnmountedVolume<D: io::Read + io::Write + io::Seek> {
 }
 
 pub struct MountedVolume<D: io::Read + io::Write + io::Seek> {
-    data: D,
+    cache: BlockCache<D>,
     header: VolumeHeader,
-    xts: Xts128<Aes256>,
+    xts: XtsCipher,
+    cipher: Cipher,
+    prf: Prf,
+    /// The volume's own, user-facing cursor. Distinct from any position in
+    /// the backing store, since unit reads/writes go through `cache` by
+    /// absolute offset rather than the store's ambient cursor.
+    pos: u64,
+}
+
+impl<D: io::Read + io::Write + io::Seek> MountedVolume<D> {
+    /// The cipher (or cascade of ciphers) this volume was matched against.
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// The PRF (PBKDF2 hash function) this volume was matched against.
+    pub fn prf(&self) -> Prf {
+        self.prf
+    }
 }
 
 impl UnmountedVolume<File> {
@@ -43,80 +68,247 @@ impl UnmountedVolume<File> {
     }
 }
 
+/// The offset of the hidden volume header within a volume file, VeraCrypt's
+/// fixed layout for standard (non-system) volumes.
+const HIDDEN_HEADER_OFFSET: u64 = 0x10000;
+
+/// Offsets (from the end of the volume) of the redundant backup headers
+/// VeraCrypt embeds so a damaged primary header doesn't make a volume
+/// unrecoverable.
+const BACKUP_MAIN_HEADER_OFFSET_FROM_END: u64 = 131072;
+const BACKUP_HIDDEN_HEADER_OFFSET_FROM_END: u64 = 65536;
+
+/// Which header a volume was mounted from: the outer, standard volume, or a
+/// hidden volume concealed within its free space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeKind {
+    Standard,
+    Hidden,
+}
+
 impl<D: io::Read + io::Write + io::Seek> UnmountedVolume<D> {
-    pub fn mount(mut self, password: &str) -> Result<MountedFilesystem<D>, Error> {
-        // Go to the start of the volume
-        self.data.rewind().map_err(|_| Error::InvalidVolume)?;
-
-        // Read header
-        let mut header = [0; 512];
-        self.data
-            .read_exact(&mut header)
-            .map_err(|_| Error::InvalidVolume)?;
+    pub fn mount(self, password: &str) -> Result<(MountedFilesystem<D>, VolumeKind), Error> {
+        self.mount_with_pim(password, 0)
+    }
 
-        // Read salt from header
-        let salt = &header[0..64];
+    /// Mount, overriding the PBKDF2 round count with the volume's PIM
+    /// (Personal Iterations Multiplier), for volumes created with a custom
+    /// PIM. A `pim` of `0` behaves like [`Self::mount`].
+    pub fn mount_with_pim(
+        self,
+        password: &str,
+        pim: u32,
+    ) -> Result<(MountedFilesystem<D>, VolumeKind), Error> {
+        self.mount_inner(password.as_bytes(), pim)
+    }
 
-        // Derive keys from password
-        let rounds = 500000;
-        let mut key = [0; 64];
-        pbkdf2::pbkdf2::<hmac::Hmac<sha2::Sha512>>(password.as_bytes(), salt, rounds, &mut key);
+    /// Mount, folding one or more keyfiles into `password` before key
+    /// derivation, for volumes created with keyfiles in addition to (or
+    /// instead of) a password. With no keyfiles this is bit-identical to
+    /// [`Self::mount`].
+    pub fn mount_with_keyfiles(
+        self,
+        password: &str,
+        keyfiles: &[PathBuf],
+    ) -> Result<(MountedFilesystem<D>, VolumeKind), Error> {
+        if keyfiles.is_empty() {
+            return self.mount(password);
+        }
 
-        // Setup header decryption
-        let cipher_1 = Aes256::new(GenericArray::from_slice(&key[..32]));
-        let cipher_2 = Aes256::new(GenericArray::from_slice(&key[32..]));
-        let xts = Xts128::<Aes256>::new(cipher_1, cipher_2);
+        let folded = apply_keyfiles(password, keyfiles).map_err(Error::FileOpenFailure)?;
+        self.mount_inner(&folded, 0)
+    }
 
-        // Decrypt header
-        xts.decrypt_area(&mut header[64..], 448, 0, get_tweak_default);
+    fn mount_inner(
+        mut self,
+        password: &[u8],
+        pim: u32,
+    ) -> Result<(MountedFilesystem<D>, VolumeKind), Error> {
+        let total_size = self
+            .data
+            .seek(io::SeekFrom::End(0))
+            .map_err(|_| Error::InvalidVolume)?;
 
-        // Check magic value
-        if &header[64..68] != "VERA".as_bytes() {
-            return Err(Error::InvalidKey);
-        }
+        // Try the standard header, then the hidden header, then (in case
+        // either of those is damaged) VeraCrypt's redundant backup copies of
+        // both, embedded near the end of the volume.
+        let candidates = [
+            (0, VolumeKind::Standard),
+            (HIDDEN_HEADER_OFFSET, VolumeKind::Hidden),
+            (
+                total_size.saturating_sub(BACKUP_MAIN_HEADER_OFFSET_FROM_END),
+                VolumeKind::Standard,
+            ),
+            (
+                total_size.saturating_sub(BACKUP_HIDDEN_HEADER_OFFSET_FROM_END),
+                VolumeKind::Hidden,
+            ),
+        ];
+
+        let mut matched = None;
+        for (offset, kind) in candidates {
+            self.data
+                .seek(io::SeekFrom::Start(offset))
+                .map_err(|_| Error::InvalidVolume)?;
+            let mut raw_header = [0; 512];
+            if self.data.read_exact(&mut raw_header).is_err() {
+                continue;
+            }
 
-        // Check CRC
-        let chk = CRC.checksum(&header[256..512]);
-        if header[72..76] != chk.to_be_bytes() {
-            return Err(Error::InvalidKey);
-        }
-        let chk = CRC.checksum(&header[64..252]);
-        if header[252..256] != chk.to_be_bytes() {
-            return Err(Error::InvalidKey);
+            if let Ok((header, matched_cipher, matched_prf)) =
+                find_header_cipher(&raw_header, password, pim)
+            {
+                matched = Some((header, matched_cipher, matched_prf, kind));
+                break;
+            }
         }
+        let (header, matched_cipher, matched_prf, kind) = matched.ok_or(Error::InvalidKey)?;
 
-        // Decode header
-        let header =
-            VolumeHeader::read(&mut io::Cursor::new(header)).map_err(Error::InvalidHeader)?;
-
-        // Set up data decryption
-        let cipher_1 = Aes256::new(GenericArray::from_slice(&header.master_keys[..32]));
-        let cipher_2 = Aes256::new(GenericArray::from_slice(&header.master_keys[32..]));
-        let xts = Xts128::<Aes256>::new(cipher_1, cipher_2);
+        // Set up data decryption using the cipher that unlocked the header
+        let data_key = header.data_key(matched_cipher);
+        let xts = matched_cipher.build_xts(&data_key);
 
-        // Move to the start of the data area
-        self.data
-            .seek(io::SeekFrom::Start(header.master_key_scope_offset))
-            .map_err(|_| Error::InvalidVolume)?;
-
-        // Load filesystem
+        // Load filesystem, positioned at the start of the data area (the
+        // outer or hidden filesystem's own scope, per whichever header
+        // matched)
+        let pos = header.master_key_scope_offset;
         let buf_stream = BufStream::new(MountedVolume {
-            data: self.data,
+            cache: BlockCache::new(self.data),
             header,
             xts,
+            cipher: matched_cipher,
+            prf: matched_prf,
+            pos,
         });
         let fs = fatfs::FileSystem::new(buf_stream, fatfs::FsOptions::new()).unwrap();
 
-        Ok(fs)
+        Ok((fs, kind))
+    }
+}
+
+/// Fold `keyfiles` into `password` using VeraCrypt's keyfile pooling
+/// algorithm, extending the effective password to 64 bytes.
+fn apply_keyfiles(password: &str, keyfiles: &[PathBuf]) -> io::Result<[u8; 64]> {
+    let pool = keyfile_pool(keyfiles)?;
+
+    let mut combined = [0u8; 64];
+    let password_bytes = password.as_bytes();
+    for i in 0..64 {
+        let p = password_bytes.get(i).copied().unwrap_or(0);
+        combined[i] = p.wrapping_add(pool[i]);
+    }
+
+    Ok(combined)
+}
+
+/// Build VeraCrypt's 64-byte keyfile pool: for each keyfile, read at most
+/// 1MiB, running a CRC32 over the bytes read so far and mixing each
+/// intermediate CRC's bytes into the pool round-robin.
+fn keyfile_pool(keyfiles: &[PathBuf]) -> io::Result<[u8; 64]> {
+    const MAX_KEYFILE_BYTES: usize = 1_048_576;
+
+    let mut pool = [0u8; 64];
+
+    for path in keyfiles {
+        let mut file = File::open(path)?;
+        let mut digest = CRC.digest();
+        let mut write_pos = 0;
+        let mut buffer = [0; 4096];
+        let mut total_read = 0;
+
+        while total_read < MAX_KEYFILE_BYTES {
+            let to_read = buffer.len().min(MAX_KEYFILE_BYTES - total_read);
+            let n = file.read(&mut buffer[..to_read])?;
+            if n == 0 {
+                break;
+            }
+
+            for &byte in &buffer[..n] {
+                digest.update(&[byte]);
+                // VeraCrypt mixes in the raw running CRC register, never
+                // final-XORed, so undo the `xorout` that `finalize()` applies.
+                let crc = digest.clone().finalize() ^ 0xFFFF_FFFF;
+                for crc_byte in crc.to_be_bytes() {
+                    pool[write_pos] = pool[write_pos].wrapping_add(crc_byte);
+                    write_pos = (write_pos + 1) % 64;
+                }
+            }
+            total_read += n;
+        }
+    }
+
+    Ok(pool)
+}
+
+/// Trial-decrypt `raw_header` against every `(Prf, Cipher)` combination
+/// VeraCrypt supports, returning the first one whose decrypted header yields
+/// the `"VERA"` magic and passing CRC32s.
+fn find_header_cipher(
+    raw_header: &[u8; 512],
+    password: &[u8],
+    pim: u32,
+) -> Result<(VolumeHeader, Cipher, Prf), Error> {
+    let salt = &raw_header[0..64];
+
+    for prf in Prf::ALL {
+        // The derived key only depends on the PRF, password, salt and
+        // length, not the cipher, so derive the longest key any cipher
+        // combination needs once per PRF and slice a prefix of it per
+        // candidate cipher below, rather than repeating this (expensive)
+        // PBKDF2 derivation for every cipher.
+        let max_key_len = Cipher::ALL
+            .iter()
+            .map(Cipher::key_material_len)
+            .max()
+            .unwrap();
+        let mut derived_key = vec![0; max_key_len];
+        prf.derive_key(password, salt, prf.iterations(pim), &mut derived_key);
+
+        for candidate_cipher in Cipher::ALL {
+            let key = &derived_key[..candidate_cipher.key_material_len()];
+            let xts = candidate_cipher.build_xts(key);
+
+            // Decrypt header
+            let mut header = *raw_header;
+            xts.decrypt_area(&mut header[64..], 448, 0, get_tweak_default);
+
+            // Check magic value
+            if &header[64..68] != "VERA".as_bytes() {
+                continue;
+            }
+
+            // Check CRC
+            let chk = CRC.checksum(&header[256..512]);
+            if header[72..76] != chk.to_be_bytes() {
+                continue;
+            }
+            let chk = CRC.checksum(&header[64..252]);
+            if header[252..256] != chk.to_be_bytes() {
+                continue;
+            }
+
+            // Decode header
+            let header = VolumeHeader::read(&mut io::Cursor::new(header))
+                .map_err(Error::InvalidHeader)?;
+
+            return Ok((header, candidate_cipher, prf));
+        }
     }
+
+    Err(Error::InvalidKey)
+}
+
+/// Compute the XTS tweak index for the data unit at absolute offset
+/// `unit_offset`. Each unit has its own tweak, indexed by the sector it
+/// starts at, so this must be recomputed per unit rather than held constant
+/// across a multi-unit read/write.
+fn tweak_index(unit_offset: usize, sector_size: usize) -> u128 {
+    (unit_offset / sector_size).try_into().unwrap()
 }
 
 impl<D: io::Read + io::Write + io::Seek> io::Read for MountedVolume<D> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let current_pos = self.data.stream_position()? as usize;
-
-        const DATA_UNIT_SIZE: usize = 512;
-        let mut temp_buffer = [0; DATA_UNIT_SIZE as usize];
+        let current_pos = self.pos as usize;
 
         // Calculate data unit boundaries
         let base_offset = (current_pos / DATA_UNIT_SIZE) * DATA_UNIT_SIZE;
@@ -125,25 +317,19 @@ impl<D: io::Read + io::Write + io::Seek> io::Read for MountedVolume<D> {
         let r = total_bytes_to_read % DATA_UNIT_SIZE;
         let data_units_to_read = if r > 0 { d + 1 } else { d };
 
-        // Move backwards so we end up on a data unit boundary
         let read_offset = current_pos - base_offset;
-        self.data
-            .seek(io::SeekFrom::Current(-(read_offset as i64)))?;
         let read_len = buf.len();
 
+        let sector_size: usize = self.header.sector_size.try_into().unwrap();
         let mut bytes_written = 0;
         for i in 0..data_units_to_read {
-            // Read data unit
-            self.data.read_exact(&mut temp_buffer)?;
-
-            // Decrypt
-            let sector_size = self.header.sector_size.try_into().unwrap();
-            self.xts.decrypt_area(
-                &mut temp_buffer,
-                sector_size,
-                (current_pos as usize / sector_size).try_into().unwrap(),
-                get_tweak_default,
-            );
+            let unit_offset = base_offset + i * DATA_UNIT_SIZE;
+            let tweak_index = tweak_index(unit_offset, sector_size);
+            let xts = &self.xts;
+
+            let temp_buffer = self.cache.get(unit_offset as u64, |unit| {
+                xts.decrypt_area(unit, sector_size, tweak_index, get_tweak_default)
+            })?;
 
             // Copy to user's buffer
             match i {
@@ -168,30 +354,97 @@ impl<D: io::Read + io::Write + io::Seek> io::Read for MountedVolume<D> {
                     // Middle data unit
                     // copy entire contents
                     buf[bytes_written..bytes_written + DATA_UNIT_SIZE]
-                        .copy_from_slice(&temp_buffer);
+                        .copy_from_slice(temp_buffer);
                 }
             }
         }
 
+        self.pos += bytes_written as u64;
         Ok(bytes_written)
     }
 }
 
 impl<D: io::Read + io::Write + io::Seek> io::Write for MountedVolume<D> {
-    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
-        // TODO: Encrypt data
-        //unimplemented!();
-        Ok(1)
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let current_pos = self.pos as usize;
+
+        // Clamp the write to the bounds of the volume's data area
+        let scope_start = self.header.master_key_scope_offset as usize;
+        let scope_end = scope_start + self.header.master_key_scope_size as usize;
+        let write_len = buf.len().min(scope_end.saturating_sub(current_pos));
+        if write_len == 0 {
+            return Ok(0);
+        }
+
+        // Calculate data unit boundaries
+        let base_offset = (current_pos / DATA_UNIT_SIZE) * DATA_UNIT_SIZE;
+        let total_bytes_to_write = current_pos + write_len - base_offset;
+        let d = total_bytes_to_write / DATA_UNIT_SIZE;
+        let r = total_bytes_to_write % DATA_UNIT_SIZE;
+        let data_units_to_write = if r > 0 { d + 1 } else { d };
+
+        let write_offset = current_pos - base_offset;
+
+        let sector_size: usize = self.header.sector_size.try_into().unwrap();
+        let mut bytes_written = 0;
+        for i in 0..data_units_to_write {
+            let unit_offset = base_offset + i * DATA_UNIT_SIZE;
+            let tweak_index = tweak_index(unit_offset, sector_size);
+            let xts = &self.xts;
+
+            // Read-modify-write: fetch the existing unit (decrypting on a
+            // cache miss) so we only overwrite the bytes the caller
+            // actually supplied
+            let mut unit = *self.cache.get(unit_offset as u64, |unit| {
+                xts.decrypt_area(unit, sector_size, tweak_index, get_tweak_default)
+            })?;
+
+            // Patch in the caller's bytes
+            match i {
+                0 => {
+                    // First data unit
+                    // copy [xxx------] from [.......xxx]
+                    let num_bytes_of_interest = (DATA_UNIT_SIZE - write_offset).min(write_len);
+                    unit[write_offset..write_offset + num_bytes_of_interest]
+                        .copy_from_slice(&buf[0..num_bytes_of_interest]);
+                    bytes_written += num_bytes_of_interest;
+                }
+                _ if i == data_units_to_write - 1 => {
+                    // Last data unit
+                    // copy [-------xxxx] from [xxxx.......]
+                    let mut num_bytes_of_interest = total_bytes_to_write % DATA_UNIT_SIZE;
+                    if num_bytes_of_interest == 0 {
+                        num_bytes_of_interest = DATA_UNIT_SIZE;
+                    }
+                    unit[0..num_bytes_of_interest]
+                        .copy_from_slice(&buf[write_len - num_bytes_of_interest..write_len]);
+                    bytes_written += num_bytes_of_interest;
+                }
+                _ => {
+                    // Middle data unit
+                    // copy entire contents
+                    unit.copy_from_slice(&buf[bytes_written..bytes_written + DATA_UNIT_SIZE]);
+                    bytes_written += DATA_UNIT_SIZE;
+                }
+            }
+
+            // Re-encrypt and write the unit back, caching the plaintext
+            self.cache.put(unit_offset as u64, unit, |unit| {
+                xts.encrypt_area(unit, sector_size, tweak_index, get_tweak_default)
+            })?;
+        }
+
+        self.pos += bytes_written as u64;
+        Ok(bytes_written)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.data.flush()
+        self.cache.flush()
     }
 }
 
 impl<D: io::Read + io::Write + io::Seek> io::Seek for MountedVolume<D> {
     fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-        let current_pos = self.data.stream_position()?;
         let data_start_pos = self.header.master_key_scope_offset;
         let data_len = self.header.master_key_scope_size;
 
@@ -199,13 +452,34 @@ impl<D: io::Read + io::Write + io::Seek> io::Seek for MountedVolume<D> {
         let new_pos = match pos {
             io::SeekFrom::Start(n) => data_start_pos + n,
             io::SeekFrom::End(n) => ((data_start_pos + data_len) as i64 - n) as u64,
-            io::SeekFrom::Current(n) => (current_pos as i64 + n) as u64,
+            io::SeekFrom::Current(n) => (self.pos as i64 + n) as u64,
         };
 
-        // Seek the volume
-        self.data
-            .seek(io::SeekFrom::Start(new_pos))
-            .map(|pos| pos - data_start_pos)
+        self.pos = new_pos;
+        Ok(new_pos - data_start_pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Known-answer test for `keyfile_pool`'s CRC mixing: a one-byte keyfile
+    // containing `b"A"` has a raw (non-final-XORed) CRC-32/ISO-HDLC register
+    // value of `0x2c266174`, the bitwise complement of the standard,
+    // finalized `crc32(b"A") == 0xd3d99e8b`. VeraCrypt mixes in the raw
+    // register, so the pool's first four bytes must be this value's
+    // big-endian bytes, not the finalized checksum's.
+    #[test]
+    fn keyfile_pool_uses_raw_crc_register() {
+        let path = std::env::temp_dir().join("veracrypt-test-keyfile-pool-single-byte.bin");
+        std::fs::write(&path, b"A").unwrap();
+
+        let pool = keyfile_pool(&[path.clone()]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(pool[0..4], 0x2c266174u32.to_be_bytes());
+        assert_eq!(pool[4..], [0; 60]);
     }
 }
 
@@ -227,5 +501,18 @@ pub struct VolumeHeader {
     sector_size: u32,
     #[br(pad_before = 120)]
     header_checksum: u32,
-    master_keys: [u8; 64], // NOTE: This assumes 2x256 bit keys (i.e. AES-256 mode)
+    master_keys: [u8; 64], // First 64 bytes of the data key material (a single cipher's XTS key pair)
+    secondary_keys: [u8; 192], // Remaining key material, used only by cascades
+}
+
+impl VolumeHeader {
+    /// The data decryption key material for `cipher`, laid out as this
+    /// volume's `master_keys` followed by as much of `secondary_keys` as
+    /// `cipher`'s cascade needs.
+    pub(crate) fn data_key(&self, cipher: Cipher) -> Vec<u8> {
+        let mut key = Vec::with_capacity(cipher.key_material_len());
+        key.extend_from_slice(&self.master_keys);
+        key.extend_from_slice(&self.secondary_keys[..cipher.key_material_len() - 64]);
+        key
+    }
 }
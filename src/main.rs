@@ -10,7 +10,8 @@ fn main() {
     let volume = UnmountedVolume::open(volume_path).unwrap();
 
     let password = "password1234";
-    let fs = volume.mount(password).unwrap();
+    let (fs, kind) = volume.mount(password).unwrap();
+    println!("mounted {kind:?} volume");
 
     let files: Vec<_> = fs
         .root_dir()